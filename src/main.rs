@@ -1,63 +1,426 @@
-//! Naive x86-64 code generator for expression in reverse polish form.
-//! Takes an expression on the command-line and emit nasm assembly on stdout.
+//! Code generator for a small expression/statement language, targeting either
+//! x86-64 nasm assembly or a toy register-based virtual machine.
+//! Takes a program on the command-line and emits assembly/bytecode on stdout.
 //!
-//! As the goal is to play with code generation, the input language is minimal.
-//! There is notably no lexical analyzer.  All tokens are one ASCII character long
-//! and spaces between tokens are not allowed.
+//! Input is ordinary infix source, tokenized by a small lexer. Expressions are
+//! parsed by a shunting-yard parser; statements (including `if`/`else` and
+//! `while`) are parsed by recursive descent on top of it.
 //!
 //! Grammar:
-//! program -> expr | program ';' expr
-//! expr -> primary | expr expr binary_operator
-//! primary -> number | variable
-//! number -> '0' .. '9'
-//! variable -> 'A' .. 'Z' | 'a' .. 'z'
-//! binary_operator -> '+' | '*' | '='
+//! program -> stmt*
+//! stmt -> expr ';'
+//!       | 'if' '(' expr ')' block ('else' block)?
+//!       | 'while' '(' expr ')' block
+//!       | 'break' ';' | 'continue' ';'
+//!       | block
+//! block -> '{' stmt* '}'
+//! expr -> primary | expr '+' expr | expr '-' expr | expr '*' expr
+//!       | expr '/' expr | expr '%' expr
+//!       | expr '<' expr | expr '>' expr | expr '==' expr
+//!       | expr '=' expr | '(' expr ')'
+//! primary -> number | identifier | 'syscall' '(' expr (',' expr)* ')'
+//! number -> digit+
+//! identifier -> (alpha | '_') (alpha | digit | '_')*
+//!
+//! `=` is right-associative and binds loosest, then `<`/`>`/`==`, then
+//! `+`/`-` (left-associative), then `*`/`/`/`%` (tightest).
 
 use std::collections::HashSet;
 use std::env;
-use std::fmt;
 
 fn main() {
-    let args = env::args().skip(1).collect::<Vec<String>>();
-    if args.len() != 1 {
-        panic!("usage: input_string");
+    let mut backend_name = "nasm".to_string();
+    let mut input = None;
+    for arg in env::args().skip(1) {
+        if let Some(name) = arg.strip_prefix("--backend=") {
+            backend_name = name.to_string();
+        } else {
+            input = Some(arg);
+        }
+    }
+    let input = match input {
+        Some(input) => input,
+        None => panic!("usage: [--backend=nasm|rvm] input_string"),
+    };
+
+    match backend_name.as_str() {
+        "nasm" => compile(&input, NasmBackend),
+        "rvm" => compile(&input, RvmBackend),
+        other => panic!("unknown backend: {}", other),
     }
-    compile(&args[0]);
 }
 
-/// Parses expression and calls code generator.
-fn compile(input: &str) {
-    let mut cg = CodeGen::new();
-    cg.prologue();
-    for ch in input.chars() {
-        match ch {
-            '0'..='9' => cg.number(ch.to_digit(10).unwrap()),
-            'a'..='z' | 'A'..='Z' => cg.variable(ch),
-            '+' => cg.add(),
-            '-' => cg.sub(),
-            '*' => cg.mul(),
-            ';' => cg.end_of_expr(),
-            '=' => cg.assign(),
-            _ => panic!("unexpected input: {}", ch),
+/// Tokens produced by the lexer.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(u32),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Equals,
+    EqEq,
+    Lt,
+    Gt,
+    Semi,
+    Comma,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    KwIf,
+    KwElse,
+    KwWhile,
+    KwBreak,
+    KwContinue,
+    KwSyscall,
+}
+
+/// Splits `input` into a token stream, skipping whitespace.
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' => {
+                chars.next();
+            }
+            '0'..='9' => {
+                let mut digits = String::new();
+                while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                    digits.push(d);
+                    chars.next();
+                }
+                let n = digits
+                    .parse::<u32>()
+                    .unwrap_or_else(|_| panic!("integer literal out of range: {}", digits));
+                tokens.push(Token::Int(n));
+            }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let mut s = String::new();
+                while let Some(&d) = chars.peek().filter(|d| d.is_ascii_alphanumeric() || **d == '_')
+                {
+                    s.push(d);
+                    chars.next();
+                }
+                tokens.push(match s.as_str() {
+                    "if" => Token::KwIf,
+                    "else" => Token::KwElse,
+                    "while" => Token::KwWhile,
+                    "break" => Token::KwBreak,
+                    "continue" => Token::KwContinue,
+                    "syscall" => Token::KwSyscall,
+                    _ => Token::Ident(s),
+                });
+            }
+            '+' => {
+                tokens.push(Token::Plus);
+                chars.next();
+            }
+            '-' => {
+                tokens.push(Token::Minus);
+                chars.next();
+            }
+            '*' => {
+                tokens.push(Token::Star);
+                chars.next();
+            }
+            '/' => {
+                tokens.push(Token::Slash);
+                chars.next();
+            }
+            '%' => {
+                tokens.push(Token::Percent);
+                chars.next();
+            }
+            '=' => {
+                chars.next();
+                if chars.peek() == Some(&'=') {
+                    chars.next();
+                    tokens.push(Token::EqEq);
+                } else {
+                    tokens.push(Token::Equals);
+                }
+            }
+            '<' => {
+                tokens.push(Token::Lt);
+                chars.next();
+            }
+            '>' => {
+                tokens.push(Token::Gt);
+                chars.next();
+            }
+            ';' => {
+                tokens.push(Token::Semi);
+                chars.next();
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                chars.next();
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                chars.next();
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                chars.next();
+            }
+            '{' => {
+                tokens.push(Token::LBrace);
+                chars.next();
+            }
+            '}' => {
+                tokens.push(Token::RBrace);
+                chars.next();
+            }
+            _ => panic!("unexpected input: {}", c),
         }
     }
-    cg.epilogue();
+    tokens
 }
 
-/// Naive code generator.
-/// Exposes "semantic actions" called from the parser.
-#[derive(Debug)]
-struct CodeGen {
-    // Keeps track of location of all terms of expression to generate code for.
-    stack: Vec<Location>,
-    symbols: HashSet<char>,
+/// Binding power of a binary operator token: higher binds tighter.
+fn precedence(op: &Token) -> u8 {
+    match op {
+        Token::Equals => 1,
+        Token::Lt | Token::Gt | Token::EqEq => 2,
+        Token::Plus | Token::Minus => 3,
+        Token::Star | Token::Slash | Token::Percent => 4,
+        _ => unreachable!("not a binary operator: {:?}", op),
+    }
+}
+
+fn is_right_assoc(op: &Token) -> bool {
+    matches!(op, Token::Equals)
+}
+
+/// Drives `cg`'s semantic actions for `op`; called wherever shunting-yard
+/// would push `op` to its output queue.
+fn apply<B: Backend>(op: &Token, cg: &mut CodeGen<B>) {
+    match op {
+        Token::Plus => cg.add(),
+        Token::Minus => cg.sub(),
+        Token::Star => cg.mul(),
+        Token::Slash => cg.div(),
+        Token::Percent => cg.modulo(),
+        Token::Equals => cg.assign(),
+        Token::Lt => cg.lt(),
+        Token::Gt => cg.gt(),
+        Token::EqEq => cg.eq(),
+        _ => unreachable!("not a binary operator: {:?}", op),
+    }
+}
+
+/// Recursive-descent statement parser sitting on top of the shunting-yard
+/// expression parser.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(tokens: &'a [Token]) -> Parser<'a> {
+        Parser { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn bump(&mut self) -> Token {
+        let tok = self.tokens[self.pos].clone();
+        self.pos += 1;
+        tok
+    }
+
+    fn eat(&mut self, expected: &Token) {
+        match self.peek() {
+            Some(tok) if tok == expected => {
+                self.pos += 1;
+            }
+            other => panic!("expected {:?}, got {:?}", expected, other),
+        }
+    }
+
+    /// Parses a single infix expression via shunting-yard, stopping (without
+    /// consuming) at a top-level `;` or unmatched `)`.
+    fn parse_expr<B: Backend>(&mut self, cg: &mut CodeGen<B>) {
+        let mut ops: Vec<Token> = vec![];
+        let mut depth = 0u32;
+        loop {
+            match self.peek() {
+                Some(Token::Int(_)) => {
+                    if let Token::Int(n) = self.bump() {
+                        cg.number(n);
+                    }
+                }
+                Some(Token::Ident(_)) => {
+                    if let Token::Ident(s) = self.bump() {
+                        cg.variable(s);
+                    }
+                }
+                Some(Token::KwSyscall) => {
+                    self.bump();
+                    self.eat(&Token::LParen);
+                    let mut argc = 0;
+                    if self.peek() != Some(&Token::RParen) {
+                        loop {
+                            self.parse_expr(cg);
+                            argc += 1;
+                            if self.peek() == Some(&Token::Comma) {
+                                self.pos += 1;
+                            } else {
+                                break;
+                            }
+                        }
+                    }
+                    self.eat(&Token::RParen);
+                    cg.syscall(argc);
+                }
+                Some(Token::Plus)
+                | Some(Token::Minus)
+                | Some(Token::Star)
+                | Some(Token::Slash)
+                | Some(Token::Percent)
+                | Some(Token::Equals)
+                | Some(Token::Lt)
+                | Some(Token::Gt)
+                | Some(Token::EqEq) => {
+                    let tok = self.bump();
+                    while let Some(top) = ops.last() {
+                        if *top == Token::LParen {
+                            break;
+                        }
+                        if precedence(top) > precedence(&tok)
+                            || (precedence(top) == precedence(&tok) && !is_right_assoc(&tok))
+                        {
+                            apply(&ops.pop().unwrap(), cg);
+                        } else {
+                            break;
+                        }
+                    }
+                    ops.push(tok);
+                }
+                Some(Token::LParen) => {
+                    depth += 1;
+                    self.pos += 1;
+                    ops.push(Token::LParen);
+                }
+                Some(Token::RParen) => {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
+                    self.pos += 1;
+                    loop {
+                        match ops.pop() {
+                            Some(Token::LParen) => break,
+                            Some(op) => apply(&op, cg),
+                            None => panic!("unmatched )"),
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+        while let Some(op) = ops.pop() {
+            apply(&op, cg);
+        }
+    }
+
+    fn parse_stmt<B: Backend>(&mut self, cg: &mut CodeGen<B>) {
+        match self.peek() {
+            Some(Token::KwIf) => self.parse_if(cg),
+            Some(Token::KwWhile) => self.parse_while(cg),
+            Some(Token::KwBreak) => {
+                self.pos += 1;
+                self.eat(&Token::Semi);
+                cg.emit_break();
+            }
+            Some(Token::KwContinue) => {
+                self.pos += 1;
+                self.eat(&Token::Semi);
+                cg.emit_continue();
+            }
+            Some(Token::LBrace) => self.parse_block(cg),
+            Some(_) => {
+                self.parse_expr(cg);
+                self.eat(&Token::Semi);
+                cg.end_of_expr();
+            }
+            None => (),
+        }
+    }
+
+    fn parse_block<B: Backend>(&mut self, cg: &mut CodeGen<B>) {
+        self.eat(&Token::LBrace);
+        while self.peek() != Some(&Token::RBrace) {
+            self.parse_stmt(cg);
+        }
+        self.eat(&Token::RBrace);
+    }
+
+    fn parse_if<B: Backend>(&mut self, cg: &mut CodeGen<B>) {
+        self.eat(&Token::KwIf);
+        self.eat(&Token::LParen);
+        self.parse_expr(cg);
+        self.eat(&Token::RParen);
+
+        let else_label = cg.new_label();
+        cg.jump_if_false(&else_label);
+        self.parse_block(cg);
+
+        if self.peek() == Some(&Token::KwElse) {
+            self.pos += 1;
+            let end_label = cg.new_label();
+            cg.jump(&end_label);
+            cg.place_label(&else_label);
+            self.parse_block(cg);
+            cg.place_label(&end_label);
+        } else {
+            cg.place_label(&else_label);
+        }
+    }
+
+    fn parse_while<B: Backend>(&mut self, cg: &mut CodeGen<B>) {
+        self.eat(&Token::KwWhile);
+        let top_label = cg.new_label();
+        let exit_label = cg.new_label();
+        cg.place_label(&top_label);
+        self.eat(&Token::LParen);
+        self.parse_expr(cg);
+        self.eat(&Token::RParen);
+        cg.jump_if_false(&exit_label);
+
+        cg.push_loop(top_label.clone(), exit_label.clone());
+        self.parse_block(cg);
+        cg.pop_loop();
+
+        cg.jump(&top_label);
+        cg.place_label(&exit_label);
+    }
+}
+
+/// Tokenizes, parses and drives `backend` through the code generator.
+fn compile<B: Backend>(input: &str, backend: B) {
+    let tokens = tokenize(input);
+    let mut cg = CodeGen::new(backend);
+    cg.prologue();
+    let mut parser = Parser::new(&tokens);
+    while parser.peek().is_some() {
+        parser.parse_stmt(&mut cg);
+    }
+    cg.epilogue();
 }
 
 /// Operand location.
 #[derive(Debug)]
 enum Location {
     OnOperandStack(Operand),
-    InAccumulator,
+    InRegister(u8),
     OnCpuStack,
 }
 
@@ -65,51 +428,445 @@ enum Location {
 #[derive(Debug)]
 enum Operand {
     Integer(u32),
-    Variable(char),
+    Variable(String),
+}
+
+/// Instruction-emission surface a code generation target must provide.
+/// `CodeGen` drives these in backend-neutral terms: registers are plain ids
+/// (`0..num_registers()`), and it is up to the backend to name and encode them.
+trait Backend {
+    /// Total number of general-purpose registers this backend exposes.
+    fn num_registers(&self) -> u8;
+
+    /// Registers never handed out by the allocator, e.g. x86's `edx` (clobbered
+    /// by `mul`/`idiv`) or a hard-wired zero register.
+    fn reserved_registers(&self) -> &[u8];
+
+    fn emit_prologue(&mut self);
+    /// Emits the final return and any storage declarations for `symbols`.
+    fn emit_epilogue(&mut self, symbols: &[String]);
+
+    fn emit_load_imm(&mut self, dst: u8, n: u32);
+    fn emit_load_var(&mut self, dst: u8, var: &str);
+    fn emit_store(&mut self, var: &str, src: u8);
+    fn emit_move(&mut self, dst: u8, src: u8);
+    /// Moves `src` into the backend's result-holding convention, if it isn't already there.
+    fn emit_return(&mut self, src: u8);
+
+    fn emit_add(&mut self, dst: u8, src: u8);
+    fn emit_sub(&mut self, dst: u8, src: u8);
+    fn emit_mul(&mut self, dst: u8, src: u8);
+    /// Each compares `dst` against `src` and leaves a 0/1 result in `dst`.
+    fn emit_lt(&mut self, dst: u8, src: u8);
+    fn emit_gt(&mut self, dst: u8, src: u8);
+    fn emit_eq(&mut self, dst: u8, src: u8);
+
+    /// Register the dividend must occupy before `emit_div`/`emit_mod`, if the
+    /// backend's division instruction is tied to a fixed register (`eax` for
+    /// x86's `idiv`). `None` if any register works, as for 3-operand VMs.
+    fn div_dst_reg(&self) -> Option<u8> {
+        None
+    }
+    fn emit_div(&mut self, dst: u8, src: u8);
+    fn emit_mod(&mut self, dst: u8, src: u8);
+
+    /// Spills/reloads a register to/from the backend's native call stack.
+    fn emit_spill(&mut self, reg: u8);
+    fn emit_reload(&mut self, reg: u8);
+
+    /// Registers carrying environment-call arguments, in calling-convention
+    /// order (SysV: `rdi, rsi, rdx, r10, r8, r9`; note `r10` stands in for
+    /// `rcx`, which the `syscall` instruction itself clobbers).
+    fn syscall_arg_regs(&self) -> &[u8];
+    /// Register the call number must occupy beforehand, and where the call's
+    /// return value ends up afterwards (`rax` for x86-64).
+    fn syscall_num_reg(&self) -> u8;
+    /// Registers the call instruction clobbers on its own, beyond the
+    /// argument/number registers the caller already overwrites.
+    fn syscall_clobbers(&self) -> &[u8];
+    fn emit_syscall(&mut self);
+
+    fn emit_label(&mut self, label: &str);
+    fn emit_jump(&mut self, label: &str);
+    fn emit_jump_if_zero(&mut self, reg: u8, label: &str);
+}
+
+/// x86-64 nasm backend: the generator's original (and default) target.
+struct NasmBackend;
+
+const NASM_EDX: u8 = 3;
+const NASM_REG32: [&str; 14] = [
+    "eax", "ebx", "ecx", "edx", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d",
+    "r15d",
+];
+const NASM_REG64: [&str; 14] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+const NASM_REG8: [&str; 14] = [
+    "al", "bl", "cl", "dl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b",
+    "r15b",
+];
+
+impl NasmBackend {
+    fn emit_setcc(&mut self, setcc: &str, dst: u8, src: u8) {
+        println!("\tcmp {}, {}", NASM_REG32[dst as usize], NASM_REG32[src as usize]);
+        println!("\t{} {}", setcc, NASM_REG8[dst as usize]);
+        println!("\tmovzx {}, {}", NASM_REG32[dst as usize], NASM_REG8[dst as usize]);
+    }
+}
+
+impl Backend for NasmBackend {
+    fn num_registers(&self) -> u8 {
+        14
+    }
+
+    fn reserved_registers(&self) -> &[u8] {
+        // Reserved for the `mul`/`idiv` accumulator pair; see chunk0-6.
+        &[NASM_EDX]
+    }
+
+    fn emit_prologue(&mut self) {
+        println!("global _evaluate");
+        println!("section .text");
+        println!("_evaluate:");
+    }
+
+    fn emit_epilogue(&mut self, symbols: &[String]) {
+        println!("\tret");
+        if !symbols.is_empty() {
+            println!("section .data");
+            for s in symbols {
+                println!("{}: dd 0", s);
+            }
+        }
+    }
+
+    fn emit_load_imm(&mut self, dst: u8, n: u32) {
+        println!("\tmov {}, {}", NASM_REG32[dst as usize], n);
+    }
+
+    fn emit_load_var(&mut self, dst: u8, var: &str) {
+        println!("\tmov {}, [rel {}]", NASM_REG32[dst as usize], var);
+    }
+
+    fn emit_store(&mut self, var: &str, src: u8) {
+        println!("\tmov dword [rel {}], {}", var, NASM_REG32[src as usize]);
+    }
+
+    fn emit_move(&mut self, dst: u8, src: u8) {
+        if dst != src {
+            println!("\tmov {}, {}", NASM_REG32[dst as usize], NASM_REG32[src as usize]);
+        }
+    }
+
+    fn emit_return(&mut self, src: u8) {
+        self.emit_move(0, src);
+    }
+
+    fn emit_add(&mut self, dst: u8, src: u8) {
+        println!("\tadd {}, {}", NASM_REG32[dst as usize], NASM_REG32[src as usize]);
+    }
+
+    fn emit_sub(&mut self, dst: u8, src: u8) {
+        println!("\tsub {}, {}", NASM_REG32[dst as usize], NASM_REG32[src as usize]);
+    }
+
+    fn emit_mul(&mut self, dst: u8, src: u8) {
+        println!("\timul {}, {}", NASM_REG32[dst as usize], NASM_REG32[src as usize]);
+    }
+
+    fn emit_lt(&mut self, dst: u8, src: u8) {
+        self.emit_setcc("setl", dst, src);
+    }
+
+    fn emit_gt(&mut self, dst: u8, src: u8) {
+        self.emit_setcc("setg", dst, src);
+    }
+
+    fn emit_eq(&mut self, dst: u8, src: u8) {
+        self.emit_setcc("sete", dst, src);
+    }
+
+    fn div_dst_reg(&self) -> Option<u8> {
+        // `idiv` always divides `edx:eax`; the allocator must put the dividend
+        // in `eax` (register id 0) for us.
+        Some(0)
+    }
+
+    fn emit_div(&mut self, dst: u8, src: u8) {
+        debug_assert_eq!(dst, 0, "idiv dividend must be in eax");
+        println!("\tcdq");
+        println!("\tidiv {}", NASM_REG32[src as usize]);
+    }
+
+    fn emit_mod(&mut self, dst: u8, src: u8) {
+        debug_assert_eq!(dst, 0, "idiv dividend must be in eax");
+        println!("\tcdq");
+        println!("\tidiv {}", NASM_REG32[src as usize]);
+        // The remainder lands in `edx`; move it where the allocator expects
+        // the result (`dst` i.e. `eax`).
+        println!("\tmov eax, edx");
+    }
+
+    fn emit_spill(&mut self, reg: u8) {
+        println!("\tpush {}", NASM_REG64[reg as usize]);
+    }
+
+    fn emit_reload(&mut self, reg: u8) {
+        println!("\tpop {}", NASM_REG64[reg as usize]);
+    }
+
+    fn syscall_arg_regs(&self) -> &[u8] {
+        // rdi, rsi, rdx, r10, r8, r9 (Linux puts the 4th arg in r10, not rcx,
+        // since `syscall` clobbers rcx).
+        &[5, 4, NASM_EDX, 8, 6, 7]
+    }
+
+    fn syscall_num_reg(&self) -> u8 {
+        0 // rax
+    }
+
+    fn syscall_clobbers(&self) -> &[u8] {
+        &[2, 9] // rcx, r11
+    }
+
+    fn emit_syscall(&mut self) {
+        println!("\tsyscall");
+    }
+
+    fn emit_label(&mut self, label: &str) {
+        println!("{}:", label);
+    }
+
+    fn emit_jump(&mut self, label: &str) {
+        println!("\tjmp {}", label);
+    }
+
+    fn emit_jump_if_zero(&mut self, reg: u8, label: &str) {
+        println!("\tcmp {}, 0", NASM_REG32[reg as usize]);
+        println!("\tjz {}", label);
+    }
+}
+
+/// Register virtual machine backend, in the style of holey-bytes: a load/store
+/// RISC encoding with a hard-wired zero register `r0` and three-operand
+/// arithmetic. Emits textual mnemonics rather than packed bytecode.
+struct RvmBackend;
+
+/// Id of the hard-wired zero register, reserved so the allocator never hands it out.
+const RVM_ZERO: u8 = 0;
+/// Conventional register holding a program's result, like an ABI return register.
+const RVM_RESULT: u8 = 1;
+
+impl Backend for RvmBackend {
+    fn num_registers(&self) -> u8 {
+        16
+    }
+
+    fn reserved_registers(&self) -> &[u8] {
+        &[RVM_ZERO]
+    }
+
+    fn emit_prologue(&mut self) {
+        println!("main:");
+    }
+
+    fn emit_epilogue(&mut self, symbols: &[String]) {
+        println!("\ttx");
+        for s in symbols {
+            println!(".global {}, 4", s);
+        }
+    }
+
+    fn emit_load_imm(&mut self, dst: u8, n: u32) {
+        println!("\tli r{}, {}", dst, n);
+    }
+
+    fn emit_load_var(&mut self, dst: u8, var: &str) {
+        println!("\tlg r{}, {}", dst, var);
+    }
+
+    fn emit_store(&mut self, var: &str, src: u8) {
+        println!("\tsg {}, r{}", var, src);
+    }
+
+    fn emit_move(&mut self, dst: u8, src: u8) {
+        if dst != src {
+            println!("\tcp r{}, r{}", dst, src);
+        }
+    }
+
+    fn emit_return(&mut self, src: u8) {
+        self.emit_move(RVM_RESULT, src);
+    }
+
+    fn emit_add(&mut self, dst: u8, src: u8) {
+        println!("\tadd r{}, r{}, r{}", dst, dst, src);
+    }
+
+    fn emit_sub(&mut self, dst: u8, src: u8) {
+        println!("\tsub r{}, r{}, r{}", dst, dst, src);
+    }
+
+    fn emit_mul(&mut self, dst: u8, src: u8) {
+        println!("\tmul r{}, r{}, r{}", dst, dst, src);
+    }
+
+    fn emit_lt(&mut self, dst: u8, src: u8) {
+        println!("\tclt r{}, r{}, r{}", dst, dst, src);
+    }
+
+    fn emit_gt(&mut self, dst: u8, src: u8) {
+        println!("\tcgt r{}, r{}, r{}", dst, dst, src);
+    }
+
+    fn emit_eq(&mut self, dst: u8, src: u8) {
+        println!("\tceq r{}, r{}, r{}", dst, dst, src);
+    }
+
+    fn emit_div(&mut self, dst: u8, src: u8) {
+        println!("\tdiv r{}, r{}, r{}", dst, dst, src);
+    }
+
+    fn emit_mod(&mut self, dst: u8, src: u8) {
+        println!("\tmod r{}, r{}, r{}", dst, dst, src);
+    }
+
+    fn emit_spill(&mut self, reg: u8) {
+        println!("\tpsh r{}", reg);
+    }
+
+    fn emit_reload(&mut self, reg: u8) {
+        println!("\tpop r{}", reg);
+    }
+
+    fn syscall_arg_regs(&self) -> &[u8] {
+        &[2, 3, 4, 5, 6, 7]
+    }
+
+    fn syscall_num_reg(&self) -> u8 {
+        RVM_RESULT
+    }
+
+    fn syscall_clobbers(&self) -> &[u8] {
+        &[]
+    }
+
+    fn emit_syscall(&mut self) {
+        // Environment call, in the style of holey-bytes' `@eca`.
+        println!("\teca");
+    }
+
+    fn emit_label(&mut self, label: &str) {
+        println!("{}:", label);
+    }
+
+    fn emit_jump(&mut self, label: &str) {
+        println!("\tjmp {}", label);
+    }
+
+    fn emit_jump_if_zero(&mut self, reg: u8, label: &str) {
+        println!("\tjz r{}, {}", reg, label);
+    }
 }
 
-impl fmt::Display for Operand {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Operand::Integer(n) => write!(f, "{}", n),
-            Operand::Variable(v) => write!(f, "[rel {}]", v),
+/// Tracks which general-purpose registers are currently free to hand out.
+///
+/// Registers are allocated in LIFO order from `free`; code generation never
+/// cares which physical register it gets, only that it gets one.
+#[derive(Debug)]
+struct RegAlloc {
+    free: Vec<u8>,
+    num_allocatable: usize,
+}
+
+impl RegAlloc {
+    fn new(num_regs: u8, reserved: &[u8]) -> RegAlloc {
+        let free: Vec<u8> = (0..num_regs).rev().filter(|r| !reserved.contains(r)).collect();
+        RegAlloc {
+            num_allocatable: free.len(),
+            free,
+        }
+    }
+
+    /// Hands out a free register, if any is left.
+    fn alloc(&mut self) -> Option<u8> {
+        self.free.pop()
+    }
+
+    /// Removes `r` from the free list if it's there, e.g. to claim a specific
+    /// register an instruction requires (`idiv`'s `eax`). Returns whether `r`
+    /// was free.
+    fn try_take(&mut self, r: u8) -> bool {
+        match self.free.iter().position(|&f| f == r) {
+            Some(pos) => {
+                self.free.remove(pos);
+                true
+            }
+            None => false,
         }
     }
+
+    /// Returns a register to the free list.
+    fn free(&mut self, r: u8) {
+        debug_assert!(!self.free.contains(&r), "double free of r{}", r);
+        self.free.push(r);
+    }
+
+    /// True once every allocatable register has been returned, i.e. nothing is leaked.
+    fn is_full(&self) -> bool {
+        self.free.len() == self.num_allocatable
+    }
 }
 
-impl CodeGen {
-    fn new() -> CodeGen {
+/// Code generator. Exposes "semantic actions" called from the parser, and is
+/// generic over the `Backend` that turns them into instructions.
+#[derive(Debug)]
+struct CodeGen<B> {
+    // Keeps track of location of all terms of expression to generate code for.
+    stack: Vec<Location>,
+    symbols: HashSet<String>,
+    regs: RegAlloc,
+    // Monotonic counter used to mint unique `.L<n>` labels.
+    next_label: u32,
+    // (continue_label, break_label) of each `while` loop currently being generated,
+    // innermost last.
+    loops: Vec<(String, String)>,
+    backend: B,
+}
+
+impl<B: Backend> CodeGen<B> {
+    fn new(backend: B) -> CodeGen<B> {
+        let regs = RegAlloc::new(backend.num_registers(), backend.reserved_registers());
         CodeGen {
             stack: vec![],
             symbols: HashSet::new(),
+            regs,
+            next_label: 0,
+            loops: vec![],
+            backend,
         }
     }
 
     fn prologue(&mut self) {
-        println!("global _evaluate");
-        println!("section .text");
-        println!("_evaluate:");
+        self.backend.emit_prologue();
     }
 
     fn epilogue(&mut self) {
         self.end_of_expr();
-        println!("\tret");
-
-        if self.symbols.len() > 0 {
-            println!("section .data");
-            for s in &self.symbols {
-                println!("{}: dd 0", *s);
-            }
-        }
+        let mut symbols: Vec<String> = self.symbols.iter().cloned().collect();
+        symbols.sort();
+        self.backend.emit_epilogue(&symbols);
     }
 
     fn end_of_expr(&mut self) {
-        match self.stack.pop() {
-            Some(Location::OnOperandStack(o)) => println!("\tmov eax, {}", o),
-            Some(Location::OnCpuStack) => panic!("unbalanced stack: {:?}", self.stack),
-            Some(Location::InAccumulator) | None => (),
+        if let Some(loc) = self.stack.pop() {
+            let r = self.into_register(loc);
+            self.backend.emit_return(r);
+            self.regs.free(r);
         }
         assert_eq!(self.stack.len(), 0);
+        assert!(self.regs.is_full(), "register leak: {:?}", self.regs);
     }
 
     fn number(&mut self, n: u32) {
@@ -117,94 +874,354 @@ impl CodeGen {
             .push(Location::OnOperandStack(Operand::Integer(n)))
     }
 
-    fn variable(&mut self, v: char) {
-        self.symbols.insert(v);
+    fn variable(&mut self, v: String) {
+        self.symbols.insert(v.clone());
         self.stack
             .push(Location::OnOperandStack(Operand::Variable(v)))
     }
 
     fn add(&mut self) {
-        self.rvalue_binop(|n| println!("\tadd eax, {}", n));
+        if !self.fold_binop(u32::wrapping_add) {
+            self.binop(B::emit_add);
+        }
     }
 
     fn sub(&mut self) {
-        self.rvalue_binop(|n| println!("\tsub eax, {}", n));
+        if !self.fold_binop(u32::wrapping_sub) {
+            self.binop(B::emit_sub);
+        }
     }
 
     fn mul(&mut self) {
-        self.rvalue_binop(|n| {
-            println!("\tmov ebx, {}", n);
-            println!("\tmul ebx");
-        });
+        if !self.fold_binop(u32::wrapping_mul) {
+            self.binop(B::emit_mul);
+        }
     }
 
-    fn assign(&mut self) {
-        match self.prepare_binop() {
-            (Location::OnOperandStack(Operand::Variable(v)), Location::OnOperandStack(r)) => {
-                println!("\tmov eax, {}", r);
-                println!("\tmov dword [rel {}], eax", v);
-                self.stack.push(Location::InAccumulator);
+    fn div(&mut self) {
+        self.reject_literal_zero_divisor();
+        if !self.fold_binop(|a, b| a / b) {
+            self.divmod(false);
+        }
+    }
+
+    fn modulo(&mut self) {
+        self.reject_literal_zero_divisor();
+        if !self.fold_binop(|a, b| a % b) {
+            self.divmod(true);
+        }
+    }
+
+    fn reject_literal_zero_divisor(&self) {
+        if let Some(Location::OnOperandStack(Operand::Integer(0))) = self.stack.last() {
+            panic!("division by a literal zero");
+        }
+    }
+
+    /// Shared by `div`/`modulo`: materializes the dividend into whatever
+    /// register the backend's division instruction requires (if any), then
+    /// emits the op and leaves the (quotient or remainder) result there.
+    fn divmod(&mut self, want_remainder: bool) {
+        let (lhs, mut rhs) = self.prepare_binop();
+        let dst = match self.backend.div_dst_reg() {
+            // `rhs` has already been popped off `self.stack`, so it must be
+            // passed in explicitly or it could get silently clobbered if it's
+            // already sitting in `want` (e.g. `a / (b + c)`).
+            Some(want) => self.into_specific_register(lhs, want, std::slice::from_mut(&mut rhs)),
+            None => self.into_register(lhs),
+        };
+        let src = self.into_register(rhs);
+        if want_remainder {
+            self.backend.emit_mod(dst, src);
+        } else {
+            self.backend.emit_div(dst, src);
+        }
+        self.regs.free(src);
+        self.stack.push(Location::InRegister(dst));
+    }
+
+    /// Tries to evaluate a binary op at compile time when both operands are
+    /// literal integers still sitting unmaterialized on the operand stack.
+    /// Returns whether it did; on success no code is emitted and a single
+    /// folded `Integer` takes the two operands' place.
+    fn fold_binop<F: FnOnce(u32, u32) -> u32>(&mut self, f: F) -> bool {
+        let n = self.stack.len();
+        if n < 2 {
+            return false;
+        }
+        if !matches!(
+            (&self.stack[n - 2], &self.stack[n - 1]),
+            (
+                Location::OnOperandStack(Operand::Integer(_)),
+                Location::OnOperandStack(Operand::Integer(_))
+            )
+        ) {
+            return false;
+        }
+        let (Location::OnOperandStack(Operand::Integer(b)), Location::OnOperandStack(Operand::Integer(a))) =
+            (self.stack.pop().unwrap(), self.stack.pop().unwrap())
+        else {
+            unreachable!()
+        };
+        self.stack
+            .push(Location::OnOperandStack(Operand::Integer(f(a, b))));
+        true
+    }
+
+    fn lt(&mut self) {
+        self.binop(B::emit_lt);
+    }
+
+    fn gt(&mut self) {
+        self.binop(B::emit_gt);
+    }
+
+    fn eq(&mut self) {
+        self.binop(B::emit_eq);
+    }
+
+    /// Shared by `add`/`sub`/`mul`/`lt`/`gt`/`eq`: materializes both operands
+    /// into registers, lets `emit` turn `dst`/`src` into the actual
+    /// instruction, then frees `src` and leaves the result in `dst`.
+    fn binop(&mut self, emit: impl FnOnce(&mut B, u8, u8)) {
+        let (dst, src) = self.materialize_binop_operands();
+        emit(&mut self.backend, dst, src);
+        self.regs.free(src);
+        self.stack.push(Location::InRegister(dst));
+    }
+
+    /// Environment call: pops `argc` operands (call number first, then its
+    /// arguments), materializes them into the backend's calling convention,
+    /// and leaves the call's result where the backend's return value lands.
+    fn syscall(&mut self, argc: usize) {
+        assert!(argc >= 1, "syscall() needs at least a call number");
+        let mut operands: Vec<Location> = (0..argc)
+            .map(|_| self.stack.pop().expect("missing syscall argument"))
+            .collect();
+        operands.reverse();
+
+        let arg_regs = self.backend.syscall_arg_regs().to_vec();
+        assert!(operands.len() - 1 <= arg_regs.len(), "too many syscall arguments");
+        let mut wants = vec![self.backend.syscall_num_reg()];
+        wants.extend_from_slice(&arg_regs[..operands.len() - 1]);
+
+        // The call instruction clobbers a few scratch registers on its own;
+        // spill any live partial results still parked in them first.
+        self.spill_syscall_clobbers();
+
+        // Materialize the call number, then each argument, into its assigned
+        // register. Every operand here was already popped off `self.stack`,
+        // so they must protect each other explicitly: `operands` itself is
+        // passed as `pending` on every call (the slot being materialized is
+        // a placeholder at that point, so it can't spuriously match), which
+        // also keeps earlier, already-materialized operands from getting
+        // clobbered by a later one's rescue-spill.
+        for i in 0..operands.len() {
+            let loc = std::mem::replace(&mut operands[i], Location::OnCpuStack);
+            let r = self.into_specific_register(loc, wants[i], &mut operands);
+            operands[i] = Location::InRegister(r);
+        }
+
+        // Materialization above can itself relocate an unrelated live value
+        // into one of this call's clobbered registers (e.g. to make room for
+        // an operand's target register) — recheck right before the `syscall`
+        // instruction so that relocation doesn't slip past the earlier check.
+        self.spill_syscall_clobbers();
+
+        self.backend.emit_syscall();
+        let num_reg = wants[0];
+        for &reg in &wants[1..] {
+            if !self.backend.reserved_registers().contains(&reg) {
+                self.regs.free(reg);
             }
-            (Location::OnOperandStack(Operand::Variable(v)), Location::InAccumulator) => {
-                println!("\tmov dword [rel {}], eax", v);
-                self.stack.push(Location::InAccumulator);
+        }
+        self.stack.push(Location::InRegister(num_reg));
+    }
+
+    /// Spills any live value still parked in one of this backend's
+    /// syscall-clobbered registers (e.g. `rcx`/`r11` on nasm) onto the CPU
+    /// stack and returns that register to the free-list, so the upcoming
+    /// `syscall` instruction doesn't silently overwrite it.
+    fn spill_syscall_clobbers(&mut self) {
+        for clobbered in self.backend.syscall_clobbers().to_vec() {
+            for loc in self.stack.iter_mut() {
+                if let Location::InRegister(r) = *loc {
+                    if r == clobbered {
+                        self.backend.emit_spill(r);
+                        self.regs.free(r);
+                        *loc = Location::OnCpuStack;
+                    }
+                }
             }
-            (lhs, rhs) => panic!("unexpected stack: {:?} {:?} {:?}", self.stack, lhs, rhs),
         }
     }
 
-    /// Emits code for binary operation with rvalue operands.
-    fn rvalue_binop<F: FnOnce(&str)>(&mut self, emit_binop: F) {
-        let (lhs, rhs) = self.prepare_binop();
-        match (lhs, rhs) {
-            (Location::OnOperandStack(l), Location::OnOperandStack(r)) => {
-                println!("\tmov eax, {}", l);
-                emit_binop(&r.to_string());
-                self.stack.push(Location::InAccumulator);
-            }
-            (Location::OnOperandStack(l), Location::InAccumulator) => {
-                println!("\tmov ebx, eax");
-                println!("\tmov eax, {}", l);
-                emit_binop("ebx");
-                self.stack.push(Location::InAccumulator);
-            }
-            (Location::InAccumulator, Location::OnOperandStack(r)) => {
-                emit_binop(&r.to_string());
-                self.stack.push(Location::InAccumulator);
-            }
-            (Location::OnCpuStack, Location::InAccumulator) => {
-                println!("\tpop rbx");
-                emit_binop("ebx");
-                self.stack.push(Location::InAccumulator);
+    /// Mints a fresh, unique `.L<n>` label.
+    fn new_label(&mut self) -> String {
+        let label = format!(".L{}", self.next_label);
+        self.next_label += 1;
+        label
+    }
+
+    fn place_label(&mut self, label: &str) {
+        self.backend.emit_label(label);
+    }
+
+    fn jump(&mut self, label: &str) {
+        self.backend.emit_jump(label);
+    }
+
+    /// Pops the condition left on top of the stack by a just-parsed expression
+    /// and jumps to `label` if it is zero. Like `end_of_expr`, this is a
+    /// statement boundary: it must leave the operand stack empty and every
+    /// register free so the branch's two predecessors agree on machine state.
+    fn jump_if_false(&mut self, label: &str) {
+        let cond = self.stack.pop().expect("missing condition");
+        let r = self.into_register(cond);
+        self.backend.emit_jump_if_zero(r, label);
+        self.regs.free(r);
+        assert_eq!(self.stack.len(), 0);
+        assert!(self.regs.is_full(), "register leak: {:?}", self.regs);
+    }
+
+    fn push_loop(&mut self, continue_label: String, break_label: String) {
+        self.loops.push((continue_label, break_label));
+    }
+
+    fn pop_loop(&mut self) {
+        self.loops.pop().expect("loop stack underflow");
+    }
+
+    fn emit_break(&mut self) {
+        let (_, break_label) = self.loops.last().expect("break outside a loop").clone();
+        self.jump(&break_label);
+    }
+
+    fn emit_continue(&mut self) {
+        let (continue_label, _) = self.loops.last().expect("continue outside a loop").clone();
+        self.jump(&continue_label);
+    }
+
+    fn assign(&mut self) {
+        match self.prepare_binop() {
+            (Location::OnOperandStack(Operand::Variable(v)), rhs) => {
+                let r = self.into_register(rhs);
+                self.backend.emit_store(&v, r);
+                self.stack.push(Location::InRegister(r));
             }
             (lhs, rhs) => panic!("unexpected stack: {:?} {:?} {:?}", self.stack, lhs, rhs),
         }
     }
 
-    /// Pops operands for binary operation and spill if needed.
+    /// Pops operands for a binary operation and materializes both into registers.
+    fn materialize_binop_operands(&mut self) -> (u8, u8) {
+        let (lhs, rhs) = self.prepare_binop();
+        let dst = self.into_register(lhs);
+        let src = self.into_register(rhs);
+        (dst, src)
+    }
+
+    /// Pops operands for binary operation.
     fn prepare_binop(&mut self) -> (Location, Location) {
-        // Get location of operands.
         debug_assert!(self.stack.len() >= 2);
         let rhs = self.stack.pop().unwrap();
         let lhs = self.stack.pop().unwrap();
+        (lhs, rhs)
+    }
+
+    /// Materializes a location into a register, allocating one if needed.
+    #[allow(clippy::wrong_self_convention)] // "into" names what it does to `loc`, not a `self`-by-value conversion
+    fn into_register(&mut self, loc: Location) -> u8 {
+        match loc {
+            Location::InRegister(r) => r,
+            Location::OnOperandStack(Operand::Integer(n)) => {
+                let r = self.alloc_reg();
+                self.backend.emit_load_imm(r, n);
+                r
+            }
+            Location::OnOperandStack(Operand::Variable(v)) => {
+                let r = self.alloc_reg();
+                self.backend.emit_load_var(r, &v);
+                r
+            }
+            Location::OnCpuStack => {
+                let r = self.alloc_reg();
+                self.backend.emit_reload(r);
+                r
+            }
+        }
+    }
 
-        // Spill partial result for lower-precedence operation.
-        let len = self.stack.len();
-        for (i, ol) in self.stack.iter_mut().enumerate() {
-            match ol {
-                Location::OnOperandStack(Operand::Integer(_)) => {}
-                Location::OnOperandStack(Operand::Variable(_)) => {}
-                Location::OnCpuStack => (),
-                Location::InAccumulator => {
-                    if i != len - 1 {
-                        panic!("unexpected stack: {:?}", self.stack);
+    /// Materializes a location into exactly register `want`, e.g. to satisfy
+    /// `idiv`'s fixed `eax` dividend. `want` may also be a reserved register
+    /// (e.g. `edx` for a syscall's third argument); such a register is never on
+    /// the free list, so it is simply written to.
+    ///
+    /// `pending` holds sibling operands of the same operation that have
+    /// already been popped off `self.stack` (so `self.stack` alone can't see
+    /// them) but aren't materialized yet, or were already materialized into
+    /// their own registers by an earlier call for the same operation. The
+    /// rescue-spill below must check those too, or it'll silently overwrite
+    /// a live sibling operand instead of spilling it.
+    #[allow(clippy::wrong_self_convention)] // "into" names what it does to `loc`, not a `self`-by-value conversion
+    fn into_specific_register(&mut self, loc: Location, want: u8, pending: &mut [Location]) -> u8 {
+        if let Location::InRegister(r) = loc {
+            if r == want {
+                return want;
+            }
+        }
+        if !self.regs.try_take(want) {
+            // `want` is currently held by some other live operand; relocate
+            // it so it can be repurposed. Prefer moving it to another free
+            // register over spilling to the CPU stack: a second, independent
+            // spill could land on top of one already pushed by an earlier
+            // operand of this same multi-operand operation (div's other
+            // operand, or an earlier syscall argument), and `emit_reload`
+            // has no way to tell those apart — it just pops whatever is on
+            // top. A register-to-register move carries no such ordering risk.
+            for l in pending.iter_mut().chain(self.stack.iter_mut()) {
+                if let Location::InRegister(r) = *l {
+                    if r == want {
+                        match self.regs.alloc() {
+                            Some(new_r) => {
+                                self.backend.emit_move(new_r, r);
+                                *l = Location::InRegister(new_r);
+                            }
+                            None => {
+                                self.backend.emit_spill(r);
+                                *l = Location::OnCpuStack;
+                            }
+                        }
+                        break;
                     }
-                    println!("\tpush rax");
-                    *ol = Location::OnCpuStack;
                 }
             }
         }
+        match loc {
+            Location::InRegister(r) => {
+                self.backend.emit_move(want, r);
+                self.regs.free(r);
+            }
+            Location::OnOperandStack(Operand::Integer(n)) => self.backend.emit_load_imm(want, n),
+            Location::OnOperandStack(Operand::Variable(v)) => self.backend.emit_load_var(want, &v),
+            Location::OnCpuStack => self.backend.emit_reload(want),
+        }
+        want
+    }
 
-        (lhs, rhs)
+    /// Allocates a register, spilling the oldest live in-register operand to the
+    /// CPU stack if none is free.
+    fn alloc_reg(&mut self) -> u8 {
+        if let Some(r) = self.regs.alloc() {
+            return r;
+        }
+        for loc in self.stack.iter_mut() {
+            if let Location::InRegister(r) = *loc {
+                self.backend.emit_spill(r);
+                *loc = Location::OnCpuStack;
+                return r;
+            }
+        }
+        panic!("no register left to spill: {:?}", self.stack);
     }
 }